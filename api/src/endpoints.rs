@@ -1,3 +1,5 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt::format;
 use std::sync::Arc;
 use chrono::{Datelike, Local, TimeZone};
@@ -20,7 +22,7 @@ use rocket::serde::json::serde_json;
 use rocket_db_pools::sqlx::{Execute, Row};
 use rocket_db_pools::sqlx::sqlite::SqliteRow;
 use crate::{Transport};
-use crate::liveupdates::{update_listener, Departure, Update, UpdateStore};
+use crate::liveupdates::{update_listener, AffectedEntityKind, Alert, Departure, Update, UpdateStore, VehiclePosition};
 
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -193,6 +195,7 @@ pub async fn departures_at_stop(mut db: Connection<Transport>,
         LEFT JOIN Calendar ON Trips.service_id = Calendar.service_id
         WHERE StopTimes.stop_id = ?
         AND StopTimes.departure_time >= ?
+        AND StopTimes.trip_id NOT IN (SELECT trip_id FROM Frequencies)
         AND {}
         ORDER BY StopTimes.departure_time
         LIMIT 10;", weekday_clause);
@@ -243,6 +246,87 @@ pub async fn departures_at_stop(mut db: Connection<Transport>,
             trips.push(trip);
         }
     }
+
+    // Frequency-based trips (GTFS `frequencies.txt`) don't have one StopTimes
+    // row per departure, so expand `start_time..end_time` by `headway_secs`
+    // into virtual departures, offset by this stop's delta from the trip's
+    // first stop.
+    let frequencies_query = format!("SELECT Frequencies.trip_id, Frequencies.start_time,
+        Frequencies.end_time, Frequencies.headway_secs,
+        Trips.route_id, Trips.service_id, Routes.route_short_name,
+        StopTimes.departure_time AS stop_departure_time,
+        first_stop.departure_time AS trip_start_departure_time
+        FROM Frequencies
+        JOIN Trips ON Trips.trip_id = Frequencies.trip_id
+        JOIN Routes ON Routes.route_id = Trips.route_id
+        JOIN StopTimes ON StopTimes.trip_id = Frequencies.trip_id AND StopTimes.stop_id = ?
+        JOIN (SELECT trip_id, MIN(stop_sequence) AS min_sequence FROM StopTimes GROUP BY trip_id) AS first_seq
+            ON first_seq.trip_id = Frequencies.trip_id
+        JOIN StopTimes AS first_stop
+            ON first_stop.trip_id = Frequencies.trip_id AND first_stop.stop_sequence = first_seq.min_sequence
+        LEFT JOIN Calendar ON Trips.service_id = Calendar.service_id
+        WHERE {};", weekday_clause);
+    let frequencies_query_prep = sqlx::query(frequencies_query.as_str())
+        .bind(stop_id)
+        .bind(cest_time.format("%Y%m%d").to_string())
+        .bind(cest_time.format("%Y%m%d").to_string());
+    let frequencies_rows = frequencies_query_prep.fetch_all(&mut **db).await;
+    if let Ok(rows) = frequencies_rows {
+        for row in rows {
+            let trip_id = row.get::<i64, _>("trip_id");
+            let stop_offset_secs = gtfs_time_to_seconds(&row.get::<String, _>("stop_departure_time"))
+                - gtfs_time_to_seconds(&row.get::<String, _>("trip_start_departure_time"));
+            let start_secs = gtfs_time_to_seconds(&row.get::<String, _>("start_time"));
+            let end_secs = gtfs_time_to_seconds(&row.get::<String, _>("end_time"));
+            let headway_secs = row.get::<i64, _>("headway_secs");
+            if headway_secs <= 0 {
+                continue;
+            }
+            let day_start = cest_time.date().and_hms(0, 0, 0).timestamp();
+
+            let live_data_option = scheduled_departures_option.as_ref().and_then(|scheduled_departures| {
+                scheduled_departures.iter().find(|departure| departure.trip_id == trip_id)
+            });
+
+            let mut slot_offsets = Vec::new();
+            let mut departure_offset_secs = start_secs;
+            while departure_offset_secs < end_secs {
+                slot_offsets.push(departure_offset_secs);
+                departure_offset_secs += headway_secs;
+            }
+
+            // A live update for a frequency trip describes one actual vehicle, not
+            // every headway-based slot, so it can only override the single virtual
+            // departure it's nearest to in scheduled time.
+            let live_slot_offset = live_data_option.and_then(|live_departure| {
+                slot_offsets.iter().copied().min_by_key(|&offset| {
+                    (day_start + offset + stop_offset_secs - live_departure.departure.timestamp).abs()
+                })
+            });
+
+            for offset in slot_offsets {
+                let is_nearest_slot = live_slot_offset == Some(offset);
+                let (departure_timestamp, delay, live) =
+                    if let Some(live_departure) = live_data_option.filter(|_| is_nearest_slot) {
+                        (live_departure.departure.timestamp, live_departure.departure.delay, true)
+                    } else {
+                        (day_start + offset + stop_offset_secs, 0, false)
+                    };
+                if departure_timestamp >= cest_time.timestamp() {
+                    trips.push(TripDTO {
+                        trip_id,
+                        route_id: row.get::<i64, _>("route_id"),
+                        service_id: row.get::<i64, _>("service_id"),
+                        route_short_name: row.get::<String, _>("route_short_name"),
+                        departure_timestamp,
+                        delay,
+                        live,
+                    });
+                }
+            }
+        }
+    }
+
     trips.sort_by_key(|t| t.departure_timestamp);
     if trips.len() > 10 {
         trips.truncate(10);
@@ -292,4 +376,341 @@ pub async fn live_trip_info(update_store: &State<Arc<UpdateStore>>, trip_id: i64
     } else {
         Err(Status::NotFound)
     }
+}
+
+#[get("/live/alerts/<stop_id>")]
+pub async fn live_alerts(update_store: &State<Arc<UpdateStore>>, stop_id: i64) -> Json<Vec<Alert>> {
+    let active_alerts = update_store.alerts.get(&(AffectedEntityKind::Stop, stop_id))
+        .map(|alerts| alerts.iter().filter(|alert| alert.is_active_now()).map(|alert| (*alert).clone()).collect())
+        .unwrap_or_default();
+    Json(active_alerts)
+}
+
+#[get("/live/vehicle/<trip_id>")]
+pub async fn live_vehicle_position(update_store: &State<Arc<UpdateStore>>, trip_id: i64)
+    -> Result<Json<VehiclePosition>, Status> {
+    let position_option = update_store.vehicle_positions.get(&trip_id);
+    if let Some(position) = position_option {
+        Ok(Json(position.clone()))
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+// Computes live trip progress from the stops' `GTFSTime::is_in_future`,
+// rather than trusting the `next_stop_index` recorded at ingestion time,
+// since "now" keeps moving between realtime updates.
+#[get("/trips/status/<trip_id>")]
+pub async fn trip_status(update_store: &State<Arc<UpdateStore>>, trip_id: i64)
+    -> Result<Json<TripStatusDTO>, Status> {
+    let update_option = update_store.trip_updates.get(&trip_id);
+    let update = match update_option {
+        Some(update) => update,
+        None => return Err(Status::NotFound),
+    };
+
+    if update.canceled {
+        return Ok(Json(TripStatusDTO {
+            trip_id,
+            status: "canceled".to_string(),
+            last_passed_stop_id: None,
+            next_stop_id: None,
+            canceled: true,
+        }));
+    }
+
+    let next_index = update.stops.iter().position(|stop| stop.departure.is_in_future());
+    let (status, last_passed_stop_id, next_stop_id) = match next_index {
+        Some(0) => ("not_started".to_string(), None, update.stops.first().map(|stop| stop.stop_id)),
+        Some(index) => (
+            "in_transit".to_string(),
+            Some(update.stops[index - 1].stop_id),
+            Some(update.stops[index].stop_id),
+        ),
+        None => ("completed".to_string(), update.stops.last().map(|stop| stop.stop_id), None),
+    };
+
+    Ok(Json(TripStatusDTO {
+        trip_id,
+        status,
+        last_passed_stop_id,
+        next_stop_id,
+        canceled: false,
+    }))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JourneyLegDTO {
+    trip_id: i64,
+    board_stop_id: i64,
+    alight_stop_id: i64,
+    departure_timestamp: i64,
+    arrival_timestamp: i64,
+}
+
+// A single GTFS `StopTimes` hop between two consecutive stops on a trip,
+// as scanned by the Connection Scan Algorithm.
+#[derive(Clone, Copy)]
+struct ScanConnection {
+    trip_id: i64,
+    dep_stop: i64,
+    arr_stop: i64,
+    dep_time: i64,
+    arr_time: i64,
+}
+
+fn gtfs_time_to_seconds(time: &str) -> i64 {
+    let parts: Vec<&str> = time.split(':').collect();
+    let hours: i64 = parts.get(0).and_then(|p| p.parse().ok()).unwrap_or(0);
+    let minutes: i64 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(0);
+    let seconds: i64 = parts.get(2).and_then(|p| p.parse().ok()).unwrap_or(0);
+    hours * 3600 + minutes * 60 + seconds
+}
+
+// Earliest-arrival journey planner between two stops, using the Connection
+// Scan Algorithm (Dibbelt et al.) over a day's worth of `StopTimes`, with
+// the Neo4j stop graph supplying short footpath transfers between nearby
+// platforms and `UpdateStore` overriding scheduled times with live ones.
+#[get("/journey?<from>&<to>&<departure>")]
+pub async fn journey(mut db: Connection<Transport>,
+                      graph_database: &State<Graph>,
+                      update_store: &State<Arc<UpdateStore>>,
+                      from: i64, to: i64, departure: i64)
+    -> Result<Json<Vec<JourneyLegDTO>>, Status> {
+    const FOOTPATH_TRANSFER_SECS: i64 = 120;
+
+    let departure_time = match Berlin.timestamp_opt(departure, 0).single() {
+        Some(time) => time,
+        None => return Err(Status::BadRequest),
+    };
+    let day_start = departure_time.date().and_hms(0, 0, 0).timestamp();
+    let weekday_column = match departure_time.weekday() {
+        chrono::Weekday::Mon => "monday",
+        chrono::Weekday::Tue => "tuesday",
+        chrono::Weekday::Wed => "wednesday",
+        chrono::Weekday::Thu => "thursday",
+        chrono::Weekday::Fri => "friday",
+        chrono::Weekday::Sat => "saturday",
+        chrono::Weekday::Sun => "sunday",
+    };
+
+    let sql = format!(
+        "SELECT a.trip_id AS trip_id, a.stop_id AS dep_stop, b.stop_id AS arr_stop,
+            a.departure_time AS dep_time, b.arrival_time AS arr_time
+        FROM StopTimes a
+        JOIN StopTimes b ON b.trip_id = a.trip_id AND b.stop_sequence = a.stop_sequence + 1
+        JOIN Trips ON Trips.trip_id = a.trip_id
+        LEFT JOIN Calendar ON Trips.service_id = Calendar.service_id
+        WHERE Calendar.{} = 1
+        ORDER BY a.departure_time;",
+        weekday_column
+    );
+    let rows = sqlx::query(sql.as_str()).fetch_all(&mut **db).await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let mut connections: Vec<ScanConnection> = Vec::with_capacity(rows.len());
+    for row in rows {
+        connections.push(ScanConnection {
+            trip_id: row.get::<i64, _>("trip_id"),
+            dep_stop: row.get::<i64, _>("dep_stop"),
+            arr_stop: row.get::<i64, _>("arr_stop"),
+            dep_time: day_start + gtfs_time_to_seconds(&row.get::<String, _>("dep_time")),
+            arr_time: day_start + gtfs_time_to_seconds(&row.get::<String, _>("arr_time")),
+        });
+    }
+
+    // Fold live delays from the realtime feed into the scheduled connections
+    // so routing reflects what is actually running, not the static timetable.
+    // A `StopTimeUpdate` that only reports one of arrival/departure leaves
+    // the other as an unset `GTFSTime` (timestamp 0), which must not be
+    // trusted here — an unguarded `arr_time` of 0 would win every earliest-
+    // arrival comparison in the CSA scan below and corrupt the whole result.
+    for connection in connections.iter_mut() {
+        if let Some(live_departures) = update_store.scheduled_departures.get(&connection.dep_stop) {
+            if let Some(live) = live_departures.iter().find(|d| d.trip_id == connection.trip_id) {
+                if !live.departure.is_unset() {
+                    connection.dep_time = live.departure.timestamp;
+                }
+            }
+        }
+        if let Some(live_departures) = update_store.scheduled_departures.get(&connection.arr_stop) {
+            if let Some(live) = live_departures.iter().find(|d| d.trip_id == connection.trip_id) {
+                if !live.arrival.is_unset() {
+                    connection.arr_time = live.arrival.timestamp;
+                }
+            }
+        }
+    }
+    connections.sort_by_key(|c| c.dep_time);
+
+    // Adjacent stops in the Neo4j graph double as short footpath transfers
+    // of a fixed `FOOTPATH_TRANSFER_SECS`, so changing between nearby
+    // platforms is allowed.
+    let mut footpaths: HashMap<i64, Vec<i64>> = HashMap::new();
+    let adjacency = graph_database.execute(
+        query("MATCH (s:Stop)-[:CONNECTS_TO]-(n:Stop) RETURN s.stop_id AS from_id, n.stop_id AS to_id;")
+    ).await;
+    if let Ok(mut result) = adjacency {
+        while let Ok(Some(row)) = result.next().await {
+            let from_id = row.get::<String>("from_id").ok().and_then(|s| s.parse::<i64>().ok());
+            let to_id = row.get::<String>("to_id").ok().and_then(|s| s.parse::<i64>().ok());
+            if let (Some(from_id), Some(to_id)) = (from_id, to_id) {
+                footpaths.entry(from_id).or_insert_with(Vec::new).push(to_id);
+            }
+        }
+    }
+
+    let mut earliest_arrival: HashMap<i64, i64> = HashMap::new();
+    earliest_arrival.insert(from, departure);
+    for neighbour in footpaths.get(&from).cloned().unwrap_or_default() {
+        earliest_arrival.entry(neighbour).or_insert(departure + FOOTPATH_TRANSFER_SECS);
+    }
+
+    // Maps a stop to the connection whose ride (possibly followed by a
+    // footpath walk to reach that stop) produced its earliest arrival.
+    let mut journey_pointer: HashMap<i64, ScanConnection> = HashMap::new();
+    let mut boarded_trips: std::collections::HashSet<i64> = std::collections::HashSet::new();
+
+    for connection in &connections {
+        let best_to_arrival = earliest_arrival.get(&to).copied().unwrap_or(i64::MAX);
+        if connection.dep_time > best_to_arrival {
+            break;
+        }
+        let can_catch = boarded_trips.contains(&connection.trip_id)
+            || earliest_arrival.get(&connection.dep_stop).map_or(false, |t| *t <= connection.dep_time);
+        if !can_catch {
+            continue;
+        }
+        boarded_trips.insert(connection.trip_id);
+
+        let current_best = earliest_arrival.get(&connection.arr_stop).copied().unwrap_or(i64::MAX);
+        if connection.arr_time < current_best {
+            earliest_arrival.insert(connection.arr_stop, connection.arr_time);
+            journey_pointer.insert(connection.arr_stop, *connection);
+            for neighbour in footpaths.get(&connection.arr_stop).cloned().unwrap_or_default() {
+                let transfer_arrival = connection.arr_time + FOOTPATH_TRANSFER_SECS;
+                if transfer_arrival < earliest_arrival.get(&neighbour).copied().unwrap_or(i64::MAX) {
+                    earliest_arrival.insert(neighbour, transfer_arrival);
+                    journey_pointer.insert(neighbour, *connection);
+                }
+            }
+        }
+    }
+
+    if !earliest_arrival.contains_key(&to) {
+        return Err(Status::NotFound);
+    }
+
+    // `to` can be reached by the initial footpath straight out of `from`,
+    // with no connection ever beating it -- the seeding loop above records
+    // an `earliest_arrival` for that but never a `journey_pointer`, since
+    // there's no connection to point at. That's a legitimate answer (a
+    // walk-only trip needs no transit legs), so return it explicitly
+    // instead of letting the backtrack below hit a missing pointer and
+    // fall out with the same empty list by accident.
+    if to != from && !journey_pointer.contains_key(&to) {
+        return Ok(Json(Vec::new()));
+    }
+
+    // Walk the journey pointers back from `to` to `from`. A stop reached over
+    // a footpath still points at the connection whose ride got the passenger
+    // to the walk's starting stop, so each pointer is always a real leg.
+    let mut legs: Vec<JourneyLegDTO> = Vec::new();
+    let mut current_stop = to;
+    while current_stop != from {
+        let connection = match journey_pointer.get(&current_stop) {
+            Some(connection) => *connection,
+            None => break,
+        };
+        legs.push(JourneyLegDTO {
+            trip_id: connection.trip_id,
+            board_stop_id: connection.dep_stop,
+            alight_stop_id: connection.arr_stop,
+            departure_timestamp: connection.dep_time,
+            arrival_timestamp: connection.arr_time,
+        });
+        current_stop = connection.dep_stop;
+    }
+    legs.reverse();
+
+    Ok(Json(legs))
+}
+
+// Classic Wagner-Fischer edit distance, used to rank fuzzy stop-name matches.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1];
+        for (j, &b_char) in b_chars.iter().enumerate() {
+            let insertion = current_row[j] + 1;
+            let deletion = previous_row[j + 1] + 1;
+            let substitution = previous_row[j] + if a_char == b_char { 0 } else { 1 };
+            current_row.push(insertion.min(deletion).min(substitution));
+        }
+        previous_row = current_row;
+    }
+    *previous_row.last().unwrap_or(&0)
+}
+
+// Fuzzy stop-name search: every stop is ranked by normalized Levenshtein
+// distance, so minor typos (a dropped or swapped letter) still match, not
+// just names that literally contain the query as a substring. `Stops` is
+// small enough per-agency that scanning the whole table here is cheaper
+// than standing up a trigram index for it. Child platforms are grouped
+// under their `parent_station`, keeping only the best-ranked platform per
+// station.
+#[get("/stops/search?<q>&<limit>")]
+pub async fn search_stops(mut db: Connection<Transport>, q: String, limit: Option<usize>)
+    -> Result<Json<Vec<FullStopInfoDTO>>, Status> {
+    let limit = limit.unwrap_or(10);
+    let rows = sqlx::query(
+        "SELECT stop_id, stop_name, parent_station, stop_lat, stop_lon, location_type, platform_code
+        FROM Stops;"
+    )
+        .fetch_all(&mut **db)
+        .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let query_lower = q.to_lowercase();
+    let mut candidates: Vec<(f64, FullStopInfoDTO)> = Vec::new();
+    for row in rows {
+        let stop_name = row.get::<String, _>("stop_name");
+        let distance = levenshtein(&query_lower, &stop_name.to_lowercase());
+        let normalizer = query_lower.chars().count().max(stop_name.chars().count()).max(1);
+        let normalized_distance = distance as f64 / normalizer as f64;
+        let parent_station: Option<i64> = row.try_get("parent_station").unwrap_or(None);
+        candidates.push((normalized_distance, FullStopInfoDTO {
+            stop_id: row.get::<i64, _>("stop_id"),
+            stop_name,
+            parent_station,
+            stop_lat: row.get::<f64, _>("stop_lat"),
+            stop_lon: row.get::<f64, _>("stop_lon"),
+            location_type: row.get::<Option<String>, _>("location_type"),
+            platform_code: row.get::<Option<String>, _>("platform_code"),
+        }));
+    }
+
+    let mut best_by_station: HashMap<i64, (f64, FullStopInfoDTO)> = HashMap::new();
+    for (score, stop) in candidates {
+        let station_id = stop.parent_station.unwrap_or(stop.stop_id);
+        let is_better = best_by_station.get(&station_id)
+            .map_or(true, |(existing_score, _)| score < *existing_score);
+        if is_better {
+            best_by_station.insert(station_id, (score, stop));
+        }
+    }
+
+    let mut results: Vec<(f64, FullStopInfoDTO)> = best_by_station.into_values().collect();
+    results.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+    results.truncate(limit);
+
+    Ok(Json(results.into_iter().map(|(_, stop)| stop).collect()))
 }
\ No newline at end of file
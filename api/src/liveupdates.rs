@@ -1,7 +1,8 @@
 use std::collections::HashSet;
+use std::env;
 use std::sync::Arc;
 use std::time::Duration;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{TimeZone, Utc};
 use chrono_tz::Europe::Berlin;
 use dashmap::{DashMap, DashSet};
 use prost::Message;
@@ -45,10 +46,19 @@ impl GTFSTime {
     pub fn is_in_future(&self) -> bool {
         !self.is_in_past()
     }
+
+    // `process_stop_time_update` fills this in with a zeroed `GTFSTime` when
+    // the feed's `StopTimeUpdate` didn't carry an `arrival`/`departure`
+    // sub-message at all, so `timestamp == 0` means "not actually reported"
+    // rather than a real 1970-01-01 time.
+    pub fn is_unset(&self) -> bool {
+        self.timestamp == 0
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ScheduledStop {
+    pub stop_id: i64,
     pub stop_sequence: u32,
     pub arrival: GTFSTime,
     pub departure: GTFSTime,
@@ -86,150 +96,386 @@ impl PartialEq for Departure {
 }
 impl Eq for Departure {}
 
+// `stop_id` and `route_id` are independent GTFS id spaces and commonly
+// collide on small numeric ids, so an alert's affected entities must carry
+// which space they came from rather than being keyed by a bare `i64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AffectedEntityKind {
+    Stop,
+    Route,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    // The feed entity id this alert was parsed from. A GTFS-RT `FeedMessage`
+    // is a full snapshot, not a diff, so the same active alert reappears on
+    // every poll; this id is how we recognize "still the same alert" instead
+    // of accumulating a fresh copy each cycle.
+    pub id: String,
+    pub cause: String,
+    pub effect: String,
+    pub header_text: String,
+    pub description_text: String,
+    pub active_period_start: Option<i64>,
+    pub active_period_end: Option<i64>,
+}
+
+impl std::hash::Hash for Alert {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl PartialEq for Alert {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Alert {}
+
+impl Alert {
+    pub fn is_active_now(&self) -> bool {
+        let now = Utc::now().timestamp();
+        let started = self.active_period_start.map_or(true, |start| now >= start);
+        let not_ended = self.active_period_end.map_or(true, |end| now <= end);
+        started && not_ended
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VehiclePosition {
+    pub trip_id: i64,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub bearing: Option<f32>,
+    pub current_stop_sequence: Option<u32>,
+    pub congestion_level: String,
+    // When this position was ingested, so a stale one can be pruned even if
+    // its trip never gets a matching `trip_update` in the same poll cycle.
+    pub last_updated: i64,
+}
+
 pub struct UpdateStore {
     pub trip_updates: DashMap<i64, Update>,
     // Maps bus stop IDs to scheduled trips arriving at that stop
     pub scheduled_departures: DashMap<i64, DashSet<Departure>>,
+    // Maps affected (stop or route) entities to the alerts currently covering
+    // them, keyed within by the alert's feed entity id so a repeated
+    // snapshot overwrites rather than duplicates it.
+    pub alerts: DashMap<(AffectedEntityKind, i64), DashSet<Alert>>,
+    // Maps trip IDs to that trip's last reported vehicle position
+    pub vehicle_positions: DashMap<i64, VehiclePosition>,
 }
 
 impl UpdateStore {
     pub fn new() -> Self {
         UpdateStore {
             trip_updates: DashMap::new(),
-            scheduled_departures: DashMap::new()
+            scheduled_departures: DashMap::new(),
+            alerts: DashMap::new(),
+            vehicle_positions: DashMap::new(),
         }
     }
 }
 
+/// Errors that can occur while fetching or decoding a single GTFS-RT poll,
+/// or while processing a single entity/stop-time-update within it. These
+/// are always handled by logging and skipping the offending entity/entry;
+/// none of them should ever unwind the polling loop.
+#[derive(thiserror::Error, Debug)]
+pub enum LiveUpdateError {
+    #[error("failed to fetch GTFS-RT feed: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("failed to decode GTFS-RT feed: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("missing required field `{0}` on a feed entity")]
+    MissingField(&'static str),
+    #[error("could not parse `{value}` as a stop/trip id: {source}")]
+    ParseId {
+        value: String,
+        source: std::num::ParseIntError,
+    },
+}
+
+fn parse_id(field: &'static str, value: &str) -> Result<i64, LiveUpdateError> {
+    value.parse::<i64>().map_err(|source| LiveUpdateError::ParseId {
+        value: value.to_string(),
+        source,
+    })
+}
+
+/// Builds the `ScheduledStop`/`Departure` pair for a single stop-time-update
+/// entry within a trip update, returning an error instead of panicking when
+/// the feed omits a field this crate relies on.
+fn process_stop_time_update(
+    trip_id: i64,
+    start_date: &str,
+    stop_time_update: &gtfs::trip_update::StopTimeUpdate,
+) -> Result<(i64, ScheduledStop, Departure), LiveUpdateError> {
+    let stop_sequence = stop_time_update.stop_sequence
+        .ok_or(LiveUpdateError::MissingField("stop_time_update.stop_sequence"))?;
+
+    let arrival = if let Some(arrival) = &stop_time_update.arrival {
+        GTFSTime {
+            delay: arrival.delay.unwrap_or(0),
+            timestamp: arrival.time.ok_or(LiveUpdateError::MissingField("arrival.time"))?,
+        }
+    } else {
+        GTFSTime { delay: 0, timestamp: 0 }
+    };
+    let departure = if let Some(departure) = &stop_time_update.departure {
+        GTFSTime {
+            delay: departure.delay.unwrap_or(0),
+            timestamp: departure.time.ok_or(LiveUpdateError::MissingField("departure.time"))?,
+        }
+    } else {
+        GTFSTime { delay: 0, timestamp: 0 }
+    };
+    let canceled = stop_time_update.schedule_relationship
+        .unwrap_or(i32::from(Skipped)) == i32::from(Skipped);
+    let stop_id_field = stop_time_update.stop_id.as_ref()
+        .ok_or(LiveUpdateError::MissingField("stop_time_update.stop_id"))?;
+    let stop_id = parse_id("stop_time_update.stop_id", stop_id_field)?;
+
+    let stop_departure = Departure {
+        trip_id,
+        start_date: start_date.to_string(),
+        arrival: arrival.clone(),
+        departure: departure.clone(),
+        cancelled: canceled,
+    };
+
+    Ok((stop_id, ScheduledStop { stop_id, stop_sequence, arrival, departure, canceled }, stop_departure))
+}
+
+/// Processes a single `trip_update` entity, writing its stops into
+/// `update_store`. Returns an error rather than panicking when the entity
+/// is missing a field this crate relies on, so a single malformed entity
+/// can be logged and skipped without taking down the rest of the feed.
+fn process_trip_update(
+    trip_update: &gtfs::TripUpdate,
+    update_store: &UpdateStore,
+) -> Result<(), LiveUpdateError> {
+    let trip_id_field = trip_update.trip.trip_id.as_ref()
+        .ok_or(LiveUpdateError::MissingField("trip.trip_id"))?;
+    let trip_id = parse_id("trip.trip_id", trip_id_field)?;
+    let start_date = trip_update.trip.start_date.clone()
+        .ok_or(LiveUpdateError::MissingField("trip.start_date"))?;
+
+    let mut stops: Vec<ScheduledStop> = Vec::new();
+    for stop_time_update in &trip_update.stop_time_update {
+        let (stop_id, scheduled_stop, stop_departure) =
+            match process_stop_time_update(trip_id, &start_date, stop_time_update) {
+                Ok(result) => result,
+                Err(err) => {
+                    println!("Skipping malformed stop time update for trip {}: {}", trip_id, err);
+                    continue;
+                }
+            };
+        stops.push(scheduled_stop);
+
+        // `entry` grabs the shard lock for the whole read-modify-write, unlike a
+        // separate `contains_key`/`get_mut` pair, which another feed's poll task
+        // could interleave with (e.g. its own `retain` dropping this stop_id)
+        // between the check and the `.unwrap()`.
+        let departures = update_store.scheduled_departures.entry(stop_id).or_insert_with(DashSet::new);
+        departures.remove(&stop_departure);
+        departures.insert(stop_departure);
+    }
+
+    let update_canceled = stops.iter().all(|s| s.canceled);
+    // The next stop is the first one whose departure hasn't happened yet;
+    // once every stop has departed the trip has reached its last stop.
+    let next_stop_index = stops.iter().position(|stop| stop.departure.is_in_future())
+        .map(|index| index as i64)
+        .unwrap_or(stops.len() as i64);
+    let update = Update {
+        trip_id,
+        start_date,
+        next_stop_index,
+        stops,
+        canceled: update_canceled,
+    };
+    update_store.trip_updates.insert(trip_id, update);
+    Ok(())
+}
+
+fn translated_text(translated: &Option<gtfs::TranslatedString>) -> String {
+    translated.as_ref()
+        .and_then(|translated| translated.translation.first())
+        .map(|translation| translation.text.clone())
+        .unwrap_or_default()
+}
+
+/// Processes a single `alert` entity, storing a copy of it under every
+/// stop/route id its `informed_entity` list names.
+fn process_alert(entity_id: &str, alert: &gtfs::Alert, update_store: &UpdateStore) -> Result<(), LiveUpdateError> {
+    let cause = gtfs::alert::Cause::try_from(alert.cause.unwrap_or(0))
+        .map(|cause| format!("{:?}", cause))
+        .unwrap_or_else(|_| "UNKNOWN_CAUSE".to_string());
+    let effect = gtfs::alert::Effect::try_from(alert.effect.unwrap_or(0))
+        .map(|effect| format!("{:?}", effect))
+        .unwrap_or_else(|_| "UNKNOWN_EFFECT".to_string());
+    let (active_period_start, active_period_end) = alert.active_period.first()
+        .map(|period| (period.start, period.end))
+        .unwrap_or((None, None));
+
+    let parsed_alert = Alert {
+        id: entity_id.to_string(),
+        cause,
+        effect,
+        header_text: translated_text(&alert.header_text),
+        description_text: translated_text(&alert.description_text),
+        active_period_start,
+        active_period_end,
+    };
+
+    let mut affected_ids: Vec<(AffectedEntityKind, i64)> = Vec::new();
+    for informed_entity in &alert.informed_entity {
+        if let Some(stop_id) = informed_entity.stop_id.as_ref().and_then(|id| id.parse::<i64>().ok()) {
+            affected_ids.push((AffectedEntityKind::Stop, stop_id));
+        }
+        if let Some(route_id) = informed_entity.route_id.as_ref().and_then(|id| id.parse::<i64>().ok()) {
+            affected_ids.push((AffectedEntityKind::Route, route_id));
+        }
+    }
+    if affected_ids.is_empty() {
+        return Err(LiveUpdateError::MissingField("alert.informed_entity"));
+    }
+    for affected_id in affected_ids {
+        let alerts_for_entity = update_store.alerts.entry(affected_id).or_insert_with(DashSet::new);
+        alerts_for_entity.remove(&parsed_alert);
+        alerts_for_entity.insert(parsed_alert.clone());
+    }
+    Ok(())
+}
+
+/// Processes a single `vehicle` entity into a `VehiclePosition` keyed by
+/// the trip id it's running.
+fn process_vehicle_position(vehicle: &gtfs::VehiclePosition) -> Result<(i64, VehiclePosition), LiveUpdateError> {
+    let trip_id_field = vehicle.trip.as_ref()
+        .and_then(|trip| trip.trip_id.as_ref())
+        .ok_or(LiveUpdateError::MissingField("vehicle.trip.trip_id"))?;
+    let trip_id = parse_id("vehicle.trip.trip_id", trip_id_field)?;
+    let position = vehicle.position.as_ref()
+        .ok_or(LiveUpdateError::MissingField("vehicle.position"))?;
+    let congestion_level = gtfs::vehicle_position::CongestionLevel::try_from(vehicle.congestion_level.unwrap_or(0))
+        .map(|level| format!("{:?}", level))
+        .unwrap_or_else(|_| "UNKNOWN_CONGESTION_LEVEL".to_string());
+
+    Ok((trip_id, VehiclePosition {
+        trip_id,
+        latitude: position.latitude,
+        longitude: position.longitude,
+        bearing: position.bearing,
+        current_stop_sequence: vehicle.current_stop_sequence,
+        congestion_level,
+        last_updated: Utc::now().timestamp(),
+    }))
+}
+
+/// The GTFS-RT feeds to poll, one independently-polled feed per URL. Reads
+/// a comma-separated `GTFS_RT_URLS` env var, falling back to the single
+/// feed this crate originally shipped with.
+fn feed_urls() -> Vec<String> {
+    env::var("GTFS_RT_URLS")
+        .unwrap_or_else(|_| "https://realtime.gtfs.de/realtime-free.pb".to_string())
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+/// Spawns one independently-polled task per configured feed URL, all
+/// writing into the shared `update_store` so the dashboard can merge
+/// several agencies' realtime data.
 pub async fn update_listener(update_store: Arc<UpdateStore>) {
-    let gtfs_url = "https://realtime.gtfs.de/realtime-free.pb";
+    let urls = feed_urls();
+    let mut feeds = Vec::with_capacity(urls.len());
+    for url in urls {
+        feeds.push(tokio::spawn(poll_feed(url, Arc::clone(&update_store))));
+    }
+    for feed in feeds {
+        let _ = feed.await;
+    }
+}
+
+/// Polls a single GTFS-RT feed forever, honouring `If-Modified-Since`/
+/// `If-None-Match` so an unchanged feed (`304 Not Modified`) is skipped
+/// without decoding a body.
+async fn poll_feed(gtfs_url: String, update_store: Arc<UpdateStore>) {
     let client = reqwest::Client::builder()
         .timeout(Duration::from_secs(300)).build()
         .unwrap();
 
-    let mut last_modified: Option<DateTime<Utc>> = None;
-
+    let mut last_modified: Option<String> = None;
     let mut etag: Option<String> = None;
     loop {
         tokio::time::sleep(Duration::from_secs(1)).await;
         let start_time = Utc::now();
-        let mut client = client.get(gtfs_url).header(
+        let mut request = client.get(&gtfs_url).header(
             "User-Agent",
             "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
             Chrome/58.0.3029.110 Safari/537.3"
         );
-
-        if let Some(last_modified) = last_modified {
-            client = client.header(
-                "If-Modified-Since",
-                last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
-            );
+        if let Some(last_modified) = &last_modified {
+            request = request.header("If-Modified-Since", last_modified.as_str());
         }
         if let Some(etag) = &etag {
-            client = client.header("If-None-Match", etag);
+            request = request.header("If-None-Match", etag.as_str());
         }
-        let response = client.send().await;
-        if response.is_err() {
-            println!("Failed to fetch GTFS data: {}", response.err().unwrap());
+        let response = request.send().await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                println!("Failed to fetch GTFS-RT feed {}: {}", gtfs_url, err);
+                continue;
+            }
+        };
+
+        println!("GTFS-RT feed {} fetched with status: {}", gtfs_url, response.status());
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
             continue;
         }
-        // Print Status code
-        println!("GTFS data fetched with status: {}", response.as_ref().unwrap().status());
-        last_modified = Some(Utc::now());
-        etag = response.as_ref().unwrap().headers().get("ETag")
-            .and_then(|v| v.to_str().ok()).map(|s| s.to_string());
-        let bytes = response.unwrap().bytes().await;
-
-        if bytes.is_err() {
-            println!("Failed to read GTFS data bytes: {}", bytes.err().unwrap());
-            continue;
+        if let Some(header) = response.headers().get("Last-Modified").and_then(|v| v.to_str().ok()) {
+            last_modified = Some(header.to_string());
         }
-        let feed = FeedMessage::decode(bytes.unwrap().as_ref());
-        if feed.is_err() {
-            println!("Failed to decode GTFS feed: {}", feed.err().unwrap());
-            continue;
+        if let Some(header) = response.headers().get("ETag").and_then(|v| v.to_str().ok()) {
+            etag = Some(header.to_string());
         }
-        for entity in &feed.as_ref().unwrap().entity {
+
+        let bytes = response.bytes().await;
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                println!("Failed to read GTFS-RT feed {} bytes: {}", gtfs_url, err);
+                continue;
+            }
+        };
+        let feed = match FeedMessage::decode(bytes.as_ref()) {
+            Ok(feed) => feed,
+            Err(err) => {
+                println!("Failed to decode GTFS-RT feed {}: {}", gtfs_url, err);
+                continue;
+            }
+        };
+
+        for entity in &feed.entity {
             if let Some(trip_update) = &entity.trip_update {
-                let trip_id: i64 = trip_update.trip.trip_id.as_ref()
-                    .expect("Trip ID is Faulty in the update")
-                    .parse().unwrap_or(-1);
-                if trip_id == -1 {
-                    continue;
+                if let Err(err) = process_trip_update(trip_update, &update_store) {
+                    println!("Skipping malformed trip update entity {}: {}", entity.id, err);
                 }
-                let start_date = trip_update.trip.start_date.clone().unwrap();
-                let mut stops: Vec<ScheduledStop> = Vec::new();
-                for stop_time_update in &trip_update.stop_time_update {
-                    let stop_sequence = stop_time_update.stop_sequence.expect(
-                        "Faulty stop sequence in trip update"
-                    );
-                    let arrival = if let Some(arrival) =
-                        &stop_time_update.arrival {
-                        GTFSTime {
-                            delay: arrival.delay.unwrap_or(0),
-                            timestamp: arrival.time.expect("Arrival time missing"),
-                        }
-                    } else {
-                        GTFSTime { delay: 0, timestamp: 0 }
-                    };
-                    let departure = if let Some(departure) =
-                        &stop_time_update.departure {
-                        GTFSTime {
-                            delay: departure.delay.unwrap_or(0),
-                            timestamp: departure.time.expect("Departure time missing"),
-                        }
-                    } else {
-                        GTFSTime { delay: 0, timestamp: 0 }
-                    };
-                    let canceled = stop_time_update.schedule_relationship
-                        .unwrap_or(i32::from(Skipped)) == i32::from(Skipped);
-                    let stop_id = stop_time_update.stop_id.as_ref()
-                        .expect("Stop ID missing in stop time update")
-                        .parse::<i64>()
-                        .expect("Stop ID invalid in stop time update");
-
-                    let stop_departure = Departure {
-                        trip_id,
-                        start_date: start_date.clone(),
-                        arrival: arrival.clone(),
-                        departure: departure.clone(),
-                        cancelled: canceled
-                    };
-
-                    stops.push(ScheduledStop {
-                        stop_sequence,
-                        arrival,
-                        departure,
-                        canceled
-                    });
-
-                    if update_store.scheduled_departures.contains_key(&stop_id) {
-                        let departures =
-                            update_store.scheduled_departures.get_mut(&stop_id).unwrap();
-                        if departures.contains(&stop_departure) {
-                            departures.remove(&stop_departure);
-                        }
-                        departures.insert(stop_departure);
-                    } else {
-                        update_store.scheduled_departures.insert(
-                            stop_id,
-                            {
-                                let set = DashSet::new();
-                                set.insert(stop_departure);
-                                set
-                            }
-                        );
-                    }
+            }
+            if let Some(alert) = &entity.alert {
+                if let Err(err) = process_alert(&entity.id, alert, &update_store) {
+                    println!("Skipping malformed alert entity {}: {}", entity.id, err);
+                }
+            }
+            if let Some(vehicle) = &entity.vehicle {
+                match process_vehicle_position(vehicle) {
+                    Ok((trip_id, position)) => { update_store.vehicle_positions.insert(trip_id, position); }
+                    Err(err) => println!("Skipping malformed vehicle position entity {}: {}", entity.id, err),
                 }
-                let update_canceled = stops.iter().all(|s| s.canceled);
-                let update = Update {
-                    trip_id,
-                    start_date,
-                    next_stop_index: 0,
-                    stops,
-                    canceled: update_canceled
-                };
-                update_store.trip_updates.insert(trip_id, update);
             }
         }
         update_store.trip_updates.retain(|_k, v| {
@@ -244,12 +490,21 @@ pub async fn update_listener(update_store: Arc<UpdateStore>) {
             v.retain(|departure| departure.departure.is_in_future());
             !v.is_empty()
         });
-        // Clean up old scheduled departures
-
-
+        // Clean up old scheduled departures, and alerts past their active period
+        update_store.alerts.retain(|_k, v| {
+            v.retain(|alert| alert.is_active_now());
+            !v.is_empty()
+        });
+        // Drop vehicle positions that haven't been refreshed in a while, so a
+        // finished/gone-quiet trip doesn't keep serving an arbitrarily stale
+        // position forever. Vehicle and trip_update entities for the same
+        // trip can arrive on different feeds/cadences, so this can't key off
+        // `trip_updates` still holding the trip in the same poll cycle.
+        const VEHICLE_POSITION_STALE_SECS: i64 = 3600;
+        let now = Utc::now().timestamp();
+        update_store.vehicle_positions.retain(|_trip_id, v| now - v.last_updated < VEHICLE_POSITION_STALE_SECS);
 
-        let feed_message = feed.unwrap();
-        println!("GTFS Feed has {} entities", feed_message.entity.len());
-        println!("Took {} ms to process feed", (Utc::now() - start_time).num_milliseconds());
+        println!("GTFS-RT feed {} has {} entities", gtfs_url, feed.entity.len());
+        println!("Took {} ms to process feed {}", (Utc::now() - start_time).num_milliseconds(), gtfs_url);
     }
 }
\ No newline at end of file
@@ -32,6 +32,8 @@ async fn rocket() -> _ {
         .manage(Arc::clone(&updates))
         .mount("/", routes![
             endpoints::agency_by_id, endpoints::all_stops, endpoints::departures_at_stop,
-            endpoints::all_stops_for_trip, endpoints::get_stop_by_id
+            endpoints::all_stops_for_trip, endpoints::get_stop_by_id, endpoints::journey,
+            endpoints::live_alerts, endpoints::live_vehicle_position, endpoints::trip_status,
+            endpoints::search_stops
         ])
 }
\ No newline at end of file